@@ -0,0 +1,66 @@
+// Copyright 2015-2018 Benjamin Fry <benjaminfry@me.com>
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::fmt::{self, Display};
+use std::io;
+use std::time::Duration;
+
+/// The error type for all DNS protocol operations in this crate
+#[derive(Debug)]
+pub enum ProtoError {
+    /// An I/O error, e.g. from an underlying socket or TLS connection
+    Io(io::Error),
+    /// An HTTP/2 transport error, surfaced by the DNS-over-HTTPS client
+    #[cfg(feature = "dns-over-https")]
+    H2(h2::Error),
+    /// No response was received for a query before its timeout elapsed
+    Timeout(Duration),
+    /// An ad-hoc message describing the failure, for call sites that don't warrant a dedicated
+    ///  variant of their own
+    Message(String),
+}
+
+impl Display for ProtoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ProtoError::Io(ref e) => write!(f, "io error: {}", e),
+            #[cfg(feature = "dns-over-https")]
+            ProtoError::H2(ref e) => write!(f, "http/2 error: {}", e),
+            ProtoError::Timeout(ref duration) => {
+                write!(f, "no response received after {:?}", duration)
+            }
+            ProtoError::Message(ref s) => write!(f, "{}", s),
+        }
+    }
+}
+
+impl std::error::Error for ProtoError {}
+
+impl From<io::Error> for ProtoError {
+    fn from(e: io::Error) -> Self {
+        ProtoError::Io(e)
+    }
+}
+
+#[cfg(feature = "dns-over-https")]
+impl From<h2::Error> for ProtoError {
+    fn from(e: h2::Error) -> Self {
+        ProtoError::H2(e)
+    }
+}
+
+impl From<String> for ProtoError {
+    fn from(s: String) -> Self {
+        ProtoError::Message(s)
+    }
+}
+
+impl<'a> From<&'a str> for ProtoError {
+    fn from(s: &'a str) -> Self {
+        ProtoError::Message(s.to_string())
+    }
+}
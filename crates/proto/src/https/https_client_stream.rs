@@ -0,0 +1,233 @@
+// Copyright 2015-2018 Benjamin Fry <benjaminfry@me.com>
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::fmt::{self, Display};
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use bytes::Bytes;
+use futures::future::Either;
+use futures::stream::FuturesUnordered;
+use futures::sync::mpsc::{self, UnboundedReceiver};
+use futures::{future, Async, Future, Poll, Stream};
+use h2::client::{self, SendRequest};
+use http::header;
+use http::Request;
+use rustls::ClientConfig;
+use tokio::net::TcpStream as TokioTcpStream;
+use tokio_rustls::TlsConnector;
+use webpki::DNSNameRef;
+
+use error::ProtoError;
+use xfer::{DnsClientStream, SerialMessage};
+use BufDnsStreamHandle;
+use DnsStreamHandle;
+
+/// the content type expected for DNS wire-format messages, per RFC 8484
+const DNS_MESSAGE_CONTENT_TYPE: &str = "application/dns-message";
+
+/// the path queried for a DoH request, per RFC 8484
+const DNS_QUERY_PATH: &str = "/dns-query";
+
+/// A DNS-over-HTTPS (RFC 8484) client stream of DNS binary packets, multiplexed over a single
+///  HTTP/2 connection.
+#[must_use = "futures do nothing unless polled"]
+pub struct HttpsClientStream {
+    name_server: SocketAddr,
+    dns_name: String,
+    message_receiver: UnboundedReceiver<SerialMessage>,
+    h2: SendRequest<Bytes>,
+    in_flight: FuturesUnordered<Box<Future<Item = SerialMessage, Error = ProtoError> + Send>>,
+}
+
+impl HttpsClientStream {
+    /// Creates a new DoH stream that will send queries to `name_server` over HTTP/2, encrypted
+    ///  with TLS using `dns_name` as the server name (SNI) to validate the certificate against.
+    ///
+    /// # Return
+    ///
+    /// a tuple of a Future Stream which will handle sending and receiving messages, and a
+    ///  handle which can be used to send messages into the stream; mirrors the shape of
+    ///  `UdpClientStream::new` so it slots into the same resolver plumbing as an alternative
+    ///  to UDP or TCP.
+    pub fn new(
+        name_server: SocketAddr,
+        dns_name: String,
+    ) -> (HttpsClientConnect, Box<DnsStreamHandle + Send>) {
+        let (message_sender, message_receiver) = mpsc::unbounded();
+
+        let mut tls_config = ClientConfig::new();
+        tls_config
+            .root_store
+            .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+        tls_config.alpn_protocols.push(b"h2".to_vec());
+        let tls_connector = TlsConnector::from(Arc::new(tls_config));
+
+        let dns_name_for_connect = dns_name.clone();
+        let connect = TokioTcpStream::connect(&name_server)
+            .map_err(ProtoError::from)
+            .and_then(move |tcp_stream| {
+                // DNSNameRef borrows from dns_name_for_connect, so it must be constructed and
+                //  consumed by `connect` in this same closure; it can't be carried across a
+                //  future boundary (e.g. via future::result) without outliving the String it
+                //  borrows from.
+                let server_name = match DNSNameRef::try_from_ascii_str(&dns_name_for_connect) {
+                    Ok(server_name) => server_name,
+                    Err(_) => {
+                        return Either::A(future::err(ProtoError::from(
+                            "invalid DNS name for TLS SNI",
+                        )))
+                    }
+                };
+
+                Either::B(
+                    tls_connector
+                        .connect(server_name, tcp_stream)
+                        .map_err(ProtoError::from),
+                )
+            }).and_then(|tls_stream| client::handshake(tls_stream).map_err(ProtoError::from))
+            .map(move |(h2, connection)| {
+                // drive the underlying HTTP/2 connection to completion in the background; the
+                //  resulting responses arrive through the SendRequest handle kept on the stream
+                tokio::spawn(connection.map_err(|e| debug!("h2 connection closed: {}", e)));
+
+                HttpsClientStream {
+                    name_server,
+                    dns_name,
+                    message_receiver,
+                    h2,
+                    in_flight: FuturesUnordered::new(),
+                }
+            });
+
+        let new_future = HttpsClientConnect(Box::new(connect));
+        let sender = Box::new(BufDnsStreamHandle::new(name_server, message_sender));
+
+        (new_future, sender)
+    }
+
+    /// POSTs `message` to the `/dns-query` endpoint and returns a future resolving to the
+    ///  response body, parsed back into a `SerialMessage` carrying this stream's `name_server`.
+    fn send_message(
+        h2: &mut SendRequest<Bytes>,
+        dns_name: &str,
+        message: SerialMessage,
+    ) -> Box<Future<Item = SerialMessage, Error = ProtoError> + Send> {
+        let name_server = message.addr();
+
+        let request = Request::builder()
+            .method("POST")
+            .uri(request_uri(dns_name))
+            .header(header::CONTENT_TYPE, DNS_MESSAGE_CONTENT_TYPE)
+            .header(header::ACCEPT, DNS_MESSAGE_CONTENT_TYPE)
+            .body(())
+            .map_err(|e| ProtoError::from(format!("invalid DoH request: {}", e)));
+
+        let request = match request {
+            Ok(request) => request,
+            Err(e) => return Box::new(future::err(e)),
+        };
+
+        let (response, mut send_stream) = match h2.send_request(request, false) {
+            Ok(parts) => parts,
+            Err(e) => return Box::new(future::err(ProtoError::from(e))),
+        };
+
+        if let Err(e) = send_stream.send_data(Bytes::from(message.bytes().to_vec()), true) {
+            return Box::new(future::err(ProtoError::from(e)));
+        }
+
+        Box::new(
+            response
+                .map_err(ProtoError::from)
+                .and_then(|response| {
+                    if response.status() != http::StatusCode::OK {
+                        return Err(ProtoError::from(format!(
+                            "DoH server returned status: {}",
+                            response.status()
+                        )));
+                    }
+
+                    Ok(response.into_body())
+                }).and_then(move |recv_stream| {
+                    recv_stream
+                        .concat2()
+                        .map_err(ProtoError::from)
+                        .map(move |bytes| SerialMessage::new(bytes.to_vec(), name_server))
+                }),
+        )
+    }
+}
+
+/// The `https://{dns_name}/dns-query` URI POSTed to for every DoH request on this stream
+fn request_uri(dns_name: &str) -> String {
+    format!("https://{}{}", dns_name, DNS_QUERY_PATH)
+}
+
+impl Display for HttpsClientStream {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(formatter, "HTTPS({}, {})", self.name_server, self.dns_name)
+    }
+}
+
+impl DnsClientStream for HttpsClientStream {
+    fn name_server_addr(&self) -> SocketAddr {
+        self.name_server
+    }
+}
+
+impl Stream for HttpsClientStream {
+    type Item = SerialMessage;
+    type Error = ProtoError;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        let mut message_receiver_done = false;
+
+        loop {
+            match self.message_receiver.poll() {
+                Ok(Async::Ready(Some(message))) => {
+                    let query = Self::send_message(&mut self.h2, &self.dns_name, message);
+                    self.in_flight.push(query);
+                    continue;
+                }
+                Ok(Async::Ready(None)) | Err(()) => {
+                    message_receiver_done = true;
+                    break;
+                }
+                Ok(Async::NotReady) => break,
+            }
+        }
+
+        match self.in_flight.poll()? {
+            Async::Ready(Some(message)) => Ok(Async::Ready(Some(message))),
+            // no queries in flight and no more will ever arrive: the stream is done
+            Async::Ready(None) if message_receiver_done => Ok(Async::Ready(None)),
+            Async::Ready(None) => Ok(Async::NotReady),
+            Async::NotReady => Ok(Async::NotReady),
+        }
+    }
+}
+
+/// A future that resolves to an HttpsClientStream once the TLS+HTTP/2 handshake completes
+pub struct HttpsClientConnect(Box<Future<Item = HttpsClientStream, Error = ProtoError> + Send>);
+
+impl Future for HttpsClientConnect {
+    type Item = HttpsClientStream;
+    type Error = ProtoError;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        self.0.poll()
+    }
+}
+
+#[test]
+fn test_doh_request_uri() {
+    assert_eq!(
+        request_uri("dns.example.com"),
+        "https://dns.example.com/dns-query"
+    );
+}
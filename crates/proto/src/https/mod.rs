@@ -0,0 +1,12 @@
+// Copyright 2015-2018 Benjamin Fry <benjaminfry@me.com>
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! DNS over HTTPS (DoH, RFC 8484) client support
+
+mod https_client_stream;
+
+pub use self::https_client_stream::{HttpsClientConnect, HttpsClientStream};
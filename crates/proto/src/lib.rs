@@ -0,0 +1,26 @@
+// Copyright 2015-2018 Benjamin Fry <benjaminfry@me.com>
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! trust-dns-proto is the foundational DNS protocol library for all trust-dns projects
+
+#[macro_use]
+extern crate log;
+
+mod error;
+mod op;
+mod tcp;
+mod udp;
+mod xfer;
+
+#[cfg(feature = "dns-over-https")]
+mod https;
+
+pub use error::ProtoError;
+pub use xfer::{BufDnsStreamHandle, DnsClientStream, DnsStreamHandle, SerialMessage};
+
+#[cfg(feature = "dns-over-https")]
+pub use https::{HttpsClientConnect, HttpsClientStream};
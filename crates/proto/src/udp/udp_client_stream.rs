@@ -5,22 +5,57 @@
 // http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
 
+use std::collections::HashMap;
 use std::fmt::{self, Display};
-use std::net::SocketAddr;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use futures::{Async, Future, Poll, Stream};
+use futures::future::{loop_fn, Loop};
+use futures::sync::mpsc::{self, UnboundedReceiver};
+use futures::{future, Async, Future, Poll, Stream};
+use rand::distributions::{Distribution, Uniform};
+use rand::thread_rng;
+use tokio::net::UdpSocket as TokioUdpSocket;
+use tokio::timer::Delay;
 
 use error::ProtoError;
+use op::Message;
+use tcp::TcpClientStream;
 use udp::UdpStream;
 use xfer::{DnsClientStream, SerialMessage};
 use BufDnsStreamHandle;
 use DnsStreamHandle;
 
+/// The default timeout for any individual query on a `UdpClientStream`, in seconds
+const DEFAULT_TIMEOUT_SECS: u64 = 5;
+
+/// The default maximum UDP payload size to receive, in bytes; per RFC 6891 (EDNS0) this is
+///  comfortably larger than the classic 512-byte limit without risking fragmentation on most
+///  paths.
+const DEFAULT_MAX_PAYLOAD_LEN: usize = 4096;
+
+/// How many times to retry binding a randomized ephemeral port before giving up
+const BIND_ATTEMPTS: usize = 10;
+
 /// A UDP client stream of DNS binary packets
 #[must_use = "futures do nothing unless polled"]
 pub struct UdpClientStream {
     name_server: SocketAddr,
-    udp_stream: UdpStream,
+    io: UdpSocketSource,
+    timeout: Duration,
+    timeout_state: TimeoutState,
+    verify_source_addr: bool,
+    tcp_fallback: bool,
+    tcp_retry: Option<Box<Future<Item = SerialMessage, Error = ProtoError> + Send>>,
+    max_payload_len: usize,
+    /// outstanding queries sent on this stream, keyed by their DNS message id, so a truncated
+    ///  response can be correlated back to the query it actually answers rather than assuming
+    ///  it answers whichever query was sent most recently
+    queries_by_id: Arc<Mutex<HashMap<u16, SerialMessage>>>,
+    outstanding: Arc<AtomicUsize>,
 }
 
 impl UdpClientStream {
@@ -28,26 +63,379 @@ impl UdpClientStream {
     ///  new UdpClients such that each new client would have a random port (reduce chance of cache
     ///  poisoning)
     ///
+    /// Uses a default timeout of 5 seconds for queries, see `with_timeout` to specify another
+    ///  value.
+    ///
     /// # Return
     ///
     /// a tuple of a Future Stream which will handle sending and receiving messsages, and a
     ///  handle which can be used to send messages into the stream.
     pub fn new(name_server: SocketAddr) -> (UdpClientConnect, Box<DnsStreamHandle + Send>) {
-        let (stream_future, sender) = UdpStream::new(name_server);
-
-        let new_future = Box::new(
-            stream_future
-                .map(move |udp_stream| UdpClientStream {
-                    name_server,
-                    udp_stream,
-                }).map_err(ProtoError::from),
-        );
+        Self::with_timeout(name_server, Duration::from_secs(DEFAULT_TIMEOUT_SECS))
+    }
+
+    /// Constructs a new UdpStream for a bound client, which will timeout for responses after the
+    ///  specified `Duration`.
+    ///
+    /// # Arguments
+    ///
+    /// * `name_server` - the address of the name server to connect to
+    /// * `timeout` - the duration to wait for a response to any individual query before
+    ///   returning a timeout error
+    pub fn with_timeout(
+        name_server: SocketAddr,
+        timeout: Duration,
+    ) -> (UdpClientConnect, Box<DnsStreamHandle + Send>) {
+        Self::with_timeout_and_source_check(name_server, timeout, true)
+    }
+
+    /// Constructs a new UdpStream as with `with_timeout`, but allows the source-address check
+    ///  on inbound datagrams to be disabled.
+    ///
+    /// By default, datagrams received from any address other than `name_server` are dropped,
+    ///  as they can not be trusted to be an authentic response and are the classic DNS cache
+    ///  poisoning / spoofing vector. Setting `verify_source_addr` to `false` disables that check,
+    ///  which is only useful for debugging against test servers that respond from an address
+    ///  other than the one queried; it should never be disabled in production.
+    pub fn with_timeout_and_source_check(
+        name_server: SocketAddr,
+        timeout: Duration,
+        verify_source_addr: bool,
+    ) -> (UdpClientConnect, Box<DnsStreamHandle + Send>) {
+        Self::build(
+            name_server,
+            timeout,
+            verify_source_addr,
+            DEFAULT_MAX_PAYLOAD_LEN,
+            false,
+        )
+    }
+
+    /// Constructs a new UdpStream as with `new`, but sized to receive UDP payloads up to
+    ///  `max_payload_len` bytes rather than the default 4096.
+    ///
+    /// `max_payload_len` is also advertised on the EDNS0 OPT record of every outgoing query sent
+    ///  on the resulting stream, so the name server knows it may reply with a datagram up to
+    ///  that size; the advertised size and the receive buffer stay in sync automatically.
+    pub fn with_buffer_size(
+        name_server: SocketAddr,
+        max_payload_len: usize,
+    ) -> (UdpClientConnect, Box<DnsStreamHandle + Send>) {
+        Self::build(
+            name_server,
+            Duration::from_secs(DEFAULT_TIMEOUT_SECS),
+            true,
+            max_payload_len,
+            false,
+        )
+    }
+
+    fn build(
+        name_server: SocketAddr,
+        timeout: Duration,
+        verify_source_addr: bool,
+        max_payload_len: usize,
+        tcp_fallback: bool,
+    ) -> (UdpClientConnect, Box<DnsStreamHandle + Send>) {
+        let (stream_future, sender) = UdpStream::with_max_payload_len(name_server, max_payload_len);
+
+        let queries_by_id = Arc::new(Mutex::new(HashMap::new()));
+        let outstanding = Arc::new(AtomicUsize::new(0));
+
+        let new_future = {
+            let queries_by_id = queries_by_id.clone();
+            let outstanding = outstanding.clone();
+            Box::new(
+                stream_future
+                    .map(move |udp_stream| UdpClientStream {
+                        name_server,
+                        io: UdpSocketSource::Persistent(udp_stream),
+                        timeout,
+                        timeout_state: TimeoutState::NotStarted,
+                        verify_source_addr,
+                        tcp_fallback,
+                        tcp_retry: None,
+                        max_payload_len,
+                        queries_by_id,
+                        outstanding,
+                    }).map_err(ProtoError::from),
+            )
+        };
         let new_future = UdpClientConnect(new_future);
 
-        let sender = Box::new(BufDnsStreamHandle::new(name_server, sender));
+        let sender: Box<DnsStreamHandle + Send> = Box::new(TrackingStreamHandle {
+            inner: Box::new(BufDnsStreamHandle::new(name_server, sender)),
+            queries_by_id,
+            outstanding,
+        });
+
+        (new_future, sender)
+    }
+
+    /// The maximum UDP payload size, in bytes, this stream is configured to receive. The
+    ///  underlying `UdpStream` already advertises this same value on the EDNS0 OPT record of
+    ///  every outgoing query, so the receive buffer and the advertised size never drift apart.
+    ///  A stream from `new_single_use` has no `UdpStream`: it receives up to this many bytes,
+    ///  but sends its queries as given, without setting an OPT record.
+    pub fn max_payload_len(&self) -> usize {
+        self.max_payload_len
+    }
+
+    /// Constructs a new UdpStream that binds a fresh, randomized ephemeral port for every
+    ///  individual query rather than sharing one long-lived socket.
+    ///
+    /// Each outbound message is sent from its own short-lived socket; any response received on
+    ///  it that doesn't come from `name_server` is discarded as a likely off-path spoofing
+    ///  attempt, and the socket keeps listening until a genuine match (or the configured
+    ///  timeout) arrives, at which point it is closed. This makes the source port unpredictable
+    ///  per query, which is a stronger defense against off-path response spoofing than
+    ///  `with_timeout`'s `name_server` check alone; query IDs are still the caller's to
+    ///  randomize, same as on every other `UdpClientStream` variant.
+    ///
+    /// Uses the default timeout; there is currently no variant that also allows overriding it,
+    ///  since single-use sockets are already intended for the hardened, opinionated path.
+    pub fn new_single_use(name_server: SocketAddr) -> (UdpClientConnect, Box<DnsStreamHandle + Send>) {
+        let (message_sender, message_receiver) = mpsc::unbounded();
+        let outstanding = Arc::new(AtomicUsize::new(0));
+        let queries_by_id = Arc::new(Mutex::new(HashMap::new()));
+
+        let stream = UdpClientStream {
+            name_server,
+            io: UdpSocketSource::SingleUse {
+                message_receiver,
+                max_payload_len: DEFAULT_MAX_PAYLOAD_LEN,
+                in_flight: None,
+            },
+            timeout: Duration::from_secs(DEFAULT_TIMEOUT_SECS),
+            timeout_state: TimeoutState::NotStarted,
+            verify_source_addr: true,
+            tcp_fallback: false,
+            tcp_retry: None,
+            max_payload_len: DEFAULT_MAX_PAYLOAD_LEN,
+            queries_by_id: queries_by_id.clone(),
+            outstanding: outstanding.clone(),
+        };
+
+        let new_future = UdpClientConnect(Box::new(future::ok(stream)));
+        let sender: Box<DnsStreamHandle + Send> = Box::new(TrackingStreamHandle {
+            inner: Box::new(BufDnsStreamHandle::new(name_server, message_sender)),
+            queries_by_id,
+            outstanding,
+        });
 
         (new_future, sender)
     }
+
+    /// Constructs a new UdpStream as with `new`, but transparently retries over TCP any
+    ///  response that comes back with the truncated (TC) bit set, per RFC 1035.
+    ///
+    /// The UDP socket is bound eagerly as usual; a TCP connection to the same `name_server` is
+    ///  only established lazily, when a truncated response is actually seen, and a new one is
+    ///  opened for each truncated response rather than reused. The
+    ///  resulting `Stream::Item` is the full TCP answer in place of the truncated UDP one, so
+    ///  the fallback is invisible to callers.
+    pub fn with_tcp_fallback(name_server: SocketAddr) -> (UdpClientConnect, Box<DnsStreamHandle + Send>) {
+        Self::build(
+            name_server,
+            Duration::from_secs(DEFAULT_TIMEOUT_SECS),
+            true,
+            DEFAULT_MAX_PAYLOAD_LEN,
+            true,
+        )
+    }
+}
+
+/// Wraps a `DnsStreamHandle`, recording every outgoing message so the `UdpClientStream` it feeds
+///  can tell how many queries are currently outstanding (to gate its per-query timeout, rather
+///  than timing out an idle stream with nothing in flight) and look one back up by its DNS
+///  message id (to re-issue the right query over TCP if its response comes back truncated).
+struct TrackingStreamHandle {
+    inner: Box<DnsStreamHandle + Send>,
+    queries_by_id: Arc<Mutex<HashMap<u16, SerialMessage>>>,
+    outstanding: Arc<AtomicUsize>,
+}
+
+impl DnsStreamHandle for TrackingStreamHandle {
+    fn send(&mut self, buffer: SerialMessage) -> Result<(), ProtoError> {
+        if let Some(id) = message_id(&buffer) {
+            self.queries_by_id
+                .lock()
+                .expect("queries_by_id lock poisoned")
+                .insert(id, buffer.clone());
+        }
+        self.outstanding.fetch_add(1, Ordering::Relaxed);
+        self.inner.send(buffer)
+    }
+}
+
+/// The source of inbound datagrams backing a `UdpClientStream`
+enum UdpSocketSource {
+    /// A single, long-lived socket shared by every query sent on this stream (the default)
+    Persistent(UdpStream),
+    /// A fresh, randomized-port socket bound, used, and torn down for each individual query
+    SingleUse {
+        message_receiver: UnboundedReceiver<SerialMessage>,
+        max_payload_len: usize,
+        in_flight: Option<Box<Future<Item = SerialMessage, Error = io::Error> + Send>>,
+    },
+}
+
+impl UdpSocketSource {
+    fn poll(&mut self) -> Poll<Option<SerialMessage>, io::Error> {
+        match *self {
+            UdpSocketSource::Persistent(ref mut udp_stream) => udp_stream.poll(),
+            UdpSocketSource::SingleUse {
+                ref mut message_receiver,
+                max_payload_len,
+                ref mut in_flight,
+            } => loop {
+                if let Some(ref mut query) = *in_flight {
+                    let message = try_ready!(query.poll());
+                    *in_flight = None;
+                    return Ok(Async::Ready(Some(message)));
+                }
+
+                match message_receiver.poll() {
+                    Ok(Async::Ready(Some(message))) => {
+                        *in_flight = Some(send_single_use(message, max_payload_len));
+                    }
+                    Ok(Async::Ready(None)) => return Ok(Async::Ready(None)),
+                    Ok(Async::NotReady) => return Ok(Async::NotReady),
+                    Err(()) => return Ok(Async::Ready(None)),
+                }
+            },
+        }
+    }
+}
+
+/// Binds a fresh, randomized local port, sends `message` to its destination, and resolves to
+///  the first response datagram (up to `max_payload_len` bytes) received on that socket that
+///  actually originates from `dest`; any other datagram is assumed to be an off-path spoofing
+///  attempt and is discarded without completing the future, so a single forged packet can't
+///  cause the genuine reply to be missed.
+fn send_single_use(
+    message: SerialMessage,
+    max_payload_len: usize,
+) -> Box<Future<Item = SerialMessage, Error = io::Error> + Send> {
+    let dest = message.addr();
+
+    let socket = match bind_random_port(dest) {
+        Ok(socket) => socket,
+        Err(e) => return Box::new(future::err(e)),
+    };
+
+    Box::new(socket.send_dgram(message.bytes().to_vec(), &dest).and_then(
+        move |(socket, _buffer)| {
+            loop_fn(socket, move |socket| {
+                socket
+                    .recv_dgram(vec![0_u8; max_payload_len])
+                    .map(move |(socket, buffer, len, addr)| {
+                        if addr == dest {
+                            Loop::Break(SerialMessage::new(buffer[..len].to_vec(), addr))
+                        } else {
+                            debug!(
+                                "{} does not match expected {}, dropping and awaiting another response",
+                                addr, dest
+                            );
+                            Loop::Continue(socket)
+                        }
+                    })
+            })
+        },
+    ))
+}
+
+/// Binds a UDP socket to a randomized ephemeral local port of the same address family as
+///  `dest`, retrying a handful of times in case of a (rare) port collision.
+pub(super) fn bind_random_port(dest: SocketAddr) -> io::Result<TokioUdpSocket> {
+    let unspecified = match dest {
+        SocketAddr::V4(..) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        SocketAddr::V6(..) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+
+    let ports = Uniform::new_inclusive(49152_u16, 65535_u16);
+    let mut rng = thread_rng();
+
+    for _ in 0..BIND_ATTEMPTS {
+        let addr = SocketAddr::new(unspecified, ports.sample(&mut rng));
+        if let Ok(socket) = TokioUdpSocket::bind(&addr) {
+            return Ok(socket);
+        }
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::AddrInUse,
+        "could not bind a randomized ephemeral UDP port",
+    ))
+}
+
+/// Returns `true` if `message` parses as a DNS message with the truncated (TC) header bit set.
+///  An unparseable message is treated as not truncated; it will be surfaced to the caller as-is
+///  and fail to parse there too, rather than being silently retried.
+fn is_truncated(message: &SerialMessage) -> bool {
+    match Message::from_vec(message.bytes()) {
+        Ok(message) => message.header().truncated(),
+        Err(e) => {
+            debug!("unable to parse message to check truncation: {}", e);
+            false
+        }
+    }
+}
+
+/// Returns `message`'s DNS message id, or `None` if it doesn't parse as a DNS message.
+fn message_id(message: &SerialMessage) -> Option<u16> {
+    Message::from_vec(message.bytes()).ok().map(|m| m.id())
+}
+
+/// Decrements `counter` by 1, saturating at 0 rather than wrapping. An unsolicited response (one
+///  that doesn't correspond to anything this stream sent, e.g. a stray or spoofed datagram from
+///  `name_server`) must never be allowed to drive `counter` below 0, since on an `AtomicUsize`
+///  that wraps to `usize::MAX` and would permanently defeat the "nothing outstanding" idle check.
+fn saturating_decrement(counter: &AtomicUsize) {
+    loop {
+        let current = counter.load(Ordering::Relaxed);
+        if current == 0 {
+            return;
+        }
+        if counter.compare_and_swap(current, current - 1, Ordering::Relaxed) == current {
+            return;
+        }
+    }
+}
+
+/// Re-issues `message` to `name_server` over a new TCP connection, resolving to the first
+///  response received on it.
+fn retry_over_tcp(
+    name_server: SocketAddr,
+    message: SerialMessage,
+) -> Box<Future<Item = SerialMessage, Error = ProtoError> + Send> {
+    let (connect, mut sender) = TcpClientStream::new(name_server);
+
+    Box::new(connect.and_then(move |tcp_stream| {
+        let queued = sender
+            .send(message)
+            .map_err(|_| ProtoError::from("failed to queue TCP fallback query"));
+
+        future::result(queued).and_then(move |()| {
+            tcp_stream
+                .into_future()
+                .map_err(|(e, _stream)| e)
+                .and_then(|(response, _stream)| {
+                    response.ok_or_else(|| {
+                        ProtoError::from("TCP fallback connection closed with no response")
+                    })
+                })
+        })
+    }))
+}
+
+/// Tracks the in-flight delay future used to time out a query with no response
+enum TimeoutState {
+    /// No timeout delay is currently armed; one is armed on the next poll that finds queries
+    ///  still outstanding
+    NotStarted,
+    /// A query was sent and is waiting on a response before `Delay` elapses
+    Awaiting(Delay),
 }
 
 impl Display for UdpClientStream {
@@ -67,20 +455,104 @@ impl Stream for UdpClientStream {
     type Error = ProtoError;
 
     fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
-        match try_ready!(self.udp_stream.poll().map_err(ProtoError::from)) {
-            Some(message) => {
-                if message.addr() != self.name_server {
-                    debug!(
-                        "{} does not match name_server: {}",
-                        message.addr(),
-                        self.name_server
-                    )
+        loop {
+            if let Some(ref mut retry) = self.tcp_retry {
+                let message = try_ready!(retry.poll());
+                self.tcp_retry = None;
+                self.timeout_state = TimeoutState::NotStarted;
+                saturating_decrement(&self.outstanding);
+                return Ok(Async::Ready(Some(message)));
+            }
+
+            match self.io.poll().map_err(ProtoError::from)? {
+                Async::Ready(Some(message)) => {
+                    if self.verify_source_addr && message.addr() != self.name_server {
+                        // a response from anywhere but the configured name_server is either a
+                        //  stray packet or a spoofed off-path response; drop it and keep polling
+                        //  rather than handing it to the caller as if it were authentic.
+                        debug!(
+                            "{} does not match name_server: {}, dropping",
+                            message.addr(),
+                            self.name_server
+                        );
+                        continue;
+                    }
+
+                    let id = message_id(&message);
+
+                    if self.tcp_fallback && is_truncated(&message) {
+                        let original_query = id.and_then(|id| {
+                            self.queries_by_id
+                                .lock()
+                                .expect("queries_by_id lock poisoned")
+                                .remove(&id)
+                        });
+
+                        if let Some(query) = original_query {
+                            debug!(
+                                "truncated response from {}, retrying original query over TCP",
+                                self.name_server
+                            );
+                            self.tcp_retry = Some(retry_over_tcp(self.name_server, query));
+                            continue;
+                        }
+
+                        // no outgoing query on record to replay; fall through and hand the
+                        //  truncated response back to the caller rather than retry blindly
+                    } else if let Some(id) = id {
+                        // this query has now been answered; stop tracking it so a long-lived,
+                        //  multiplexed stream doesn't accumulate answered queries forever
+                        self.queries_by_id
+                            .lock()
+                            .expect("queries_by_id lock poisoned")
+                            .remove(&id);
+                    }
+
+                    // we got a response, so the outstanding query is no longer waiting on a timeout
+                    self.timeout_state = TimeoutState::NotStarted;
+                    saturating_decrement(&self.outstanding);
+                    return Ok(Async::Ready(Some(message)));
                 }
+                Async::Ready(None) => return Ok(Async::Ready(None)),
+                Async::NotReady => break,
+            }
+        }
+
+        // nothing is waiting on a response, so there is nothing to time out; avoid arming (or
+        //  tearing down the whole stream over) a timer while this stream just sits idle
+        if self.outstanding.load(Ordering::Relaxed) == 0 {
+            return Ok(Async::NotReady);
+        }
+
+        if let TimeoutState::NotStarted = self.timeout_state {
+            self.timeout_state = TimeoutState::Awaiting(Delay::new(Instant::now() + self.timeout));
+        }
 
-                Ok(Async::Ready(Some(message)))
+        if let TimeoutState::Awaiting(ref mut delay) = self.timeout_state {
+            match delay.poll() {
+                Ok(Async::Ready(())) => {
+                    self.timeout_state = TimeoutState::NotStarted;
+                    // whatever was outstanding timed out; this stream has no way to know which
+                    //  query(s) are still live on the wire, so drop the count back to 0 rather
+                    //  than leave it set and arm a timeout forever on an otherwise-idle stream
+                    self.outstanding.store(0, Ordering::Relaxed);
+                    // a single-use socket's query has timed out; drop it, closing its socket
+                    if let UdpSocketSource::SingleUse { ref mut in_flight, .. } = self.io {
+                        *in_flight = None;
+                    }
+                    return Err(ProtoError::Timeout(self.timeout));
+                }
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Err(e) => {
+                    return Err(ProtoError::from(io::Error::new(
+                        io::ErrorKind::Other,
+                        format!("timer error: {}", e),
+                    )))
+                }
             }
-            None => Ok(Async::Ready(None)),
         }
+
+        Ok(Async::NotReady)
     }
 }
 
@@ -96,12 +568,6 @@ impl Future for UdpClientConnect {
     }
 }
 
-#[cfg(not(target_os = "linux"))]
-#[cfg(test)]
-use std::net::Ipv6Addr;
-#[cfg(test)]
-use std::net::{IpAddr, Ipv4Addr};
-
 #[test]
 fn test_udp_client_stream_ipv4() {
     udp_client_stream_test(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)))
@@ -169,9 +635,7 @@ fn udp_client_stream_test(server_addr: IpAddr) {
     // setup the client, which is going to run on the testing thread...
     let mut io_loop = Runtime::new().unwrap();
 
-    // the tests should run within 5 seconds... right?
-    // TODO: add timeout here, so that test never hangs...
-    // let timeout = Timeout::new(Duration::from_secs(5));
+    // the client stream now owns its own per-query timeout, so this can never hang
     let (stream, mut sender) = UdpClientStream::new(server_addr);
     let mut stream: UdpClientStream = io_loop.block_on(stream).ok().unwrap();
 
@@ -188,3 +652,408 @@ fn udp_client_stream_test(server_addr: IpAddr) {
     succeeded.store(true, std::sync::atomic::Ordering::Relaxed);
     server_handle.join().expect("server thread failed");
 }
+
+#[test]
+fn test_udp_client_stream_timeout() {
+    use tokio::runtime::current_thread::Runtime;
+
+    // bind a server that will never respond, so the client has to time out
+    let server = std::net::UdpSocket::bind(SocketAddr::new(
+        IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+        0,
+    )).unwrap();
+    let server_addr = server.local_addr().unwrap();
+
+    let mut io_loop = Runtime::new().unwrap();
+    let (stream, mut sender) = UdpClientStream::with_timeout(
+        server_addr,
+        std::time::Duration::from_millis(1),
+    );
+    let stream: UdpClientStream = io_loop.block_on(stream).ok().unwrap();
+
+    sender
+        .send(SerialMessage::new(b"DEADBEEF".to_vec(), server_addr))
+        .unwrap();
+
+    let result = io_loop.block_on(stream.into_future());
+    assert!(result.is_err(), "expected a timeout error");
+}
+
+#[test]
+fn test_udp_client_stream_drops_off_path_response() {
+    use tokio::runtime::current_thread::Runtime;
+
+    let loopback = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+    let server = std::net::UdpSocket::bind(SocketAddr::new(loopback, 0)).unwrap();
+    let server_addr = server.local_addr().unwrap();
+    let imposter = std::net::UdpSocket::bind(SocketAddr::new(loopback, 0)).unwrap();
+
+    let test_bytes: &'static [u8; 8] = b"DEADBEEF";
+
+    let server_handle = std::thread::Builder::new()
+        .name("test_udp_client_stream_drops_off_path_response:server".to_string())
+        .spawn(move || {
+            let mut buffer = [0_u8; 512];
+            let (len, client_addr) = server.recv_from(&mut buffer).expect("receive failed");
+            assert_eq!(&buffer[0..len], test_bytes);
+
+            // an off-path attacker spoofing a response from a different address...
+            imposter
+                .send_to(b"NOTREAL!", client_addr)
+                .expect("imposter send failed");
+
+            // ...followed by the real, authentic response
+            server
+                .send_to(&buffer[0..len], client_addr)
+                .expect("send failed");
+        }).unwrap();
+
+    let mut io_loop = Runtime::new().unwrap();
+    let (stream, mut sender) = UdpClientStream::new(server_addr);
+    let stream: UdpClientStream = io_loop.block_on(stream).ok().unwrap();
+
+    sender
+        .send(SerialMessage::new(test_bytes.to_vec(), server_addr))
+        .unwrap();
+
+    let (buffer, _stream) = io_loop.block_on(stream.into_future()).ok().unwrap();
+    assert_eq!(buffer.expect("no buffer received").bytes(), test_bytes);
+
+    server_handle.join().expect("server thread failed");
+}
+
+#[test]
+fn test_single_use_udp_client_stream() {
+    use tokio::runtime::current_thread::Runtime;
+
+    let server = std::net::UdpSocket::bind(SocketAddr::new(
+        IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+        0,
+    )).unwrap();
+    server
+        .set_read_timeout(Some(std::time::Duration::from_secs(5)))
+        .unwrap();
+    let server_addr = server.local_addr().unwrap();
+
+    let test_bytes: &'static [u8; 8] = b"DEADBEEF";
+    let send_recv_times = 4;
+
+    let server_handle = std::thread::Builder::new()
+        .name("test_single_use_udp_client_stream:server".to_string())
+        .spawn(move || {
+            let mut buffer = [0_u8; 512];
+            let mut source_ports = std::collections::HashSet::new();
+
+            for _ in 0..send_recv_times {
+                let (len, addr) = server.recv_from(&mut buffer).expect("receive failed");
+                assert_eq!(&buffer[0..len], test_bytes);
+
+                // every query should originate from a distinct, randomized local port
+                assert!(
+                    source_ports.insert(addr.port()),
+                    "query source port {} was reused",
+                    addr.port()
+                );
+
+                server
+                    .send_to(&buffer[0..len], addr)
+                    .expect("send failed");
+            }
+        }).unwrap();
+
+    let mut io_loop = Runtime::new().unwrap();
+    let (stream, mut sender) = UdpClientStream::new_single_use(server_addr);
+    let mut stream: UdpClientStream = io_loop.block_on(stream).ok().unwrap();
+
+    for _ in 0..send_recv_times {
+        sender
+            .send(SerialMessage::new(test_bytes.to_vec(), server_addr))
+            .unwrap();
+        let (buffer, stream_tmp) = io_loop.block_on(stream.into_future()).ok().unwrap();
+        stream = stream_tmp;
+        assert_eq!(buffer.expect("no buffer received").bytes(), test_bytes);
+    }
+
+    server_handle.join().expect("server thread failed");
+}
+
+#[test]
+fn test_single_use_udp_client_stream_drops_off_path_response() {
+    use tokio::runtime::current_thread::Runtime;
+
+    let loopback = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+    let server = std::net::UdpSocket::bind(SocketAddr::new(loopback, 0)).unwrap();
+    let server_addr = server.local_addr().unwrap();
+    let imposter = std::net::UdpSocket::bind(SocketAddr::new(loopback, 0)).unwrap();
+
+    let test_bytes: &'static [u8; 8] = b"DEADBEEF";
+
+    let server_handle = std::thread::Builder::new()
+        .name("test_single_use_udp_client_stream_drops_off_path_response:server".to_string())
+        .spawn(move || {
+            let mut buffer = [0_u8; 512];
+            let (len, client_addr) = server.recv_from(&mut buffer).expect("receive failed");
+            assert_eq!(&buffer[0..len], test_bytes);
+
+            // an off-path attacker spoofing a response from a different address, arriving
+            //  before the genuine one...
+            imposter
+                .send_to(b"NOTREAL!", client_addr)
+                .expect("imposter send failed");
+
+            // ...should be dropped rather than consume the single-use socket's one response
+            server
+                .send_to(&buffer[0..len], client_addr)
+                .expect("send failed");
+        }).unwrap();
+
+    let mut io_loop = Runtime::new().unwrap();
+    let (stream, mut sender) = UdpClientStream::new_single_use(server_addr);
+    let stream: UdpClientStream = io_loop.block_on(stream).ok().unwrap();
+
+    sender
+        .send(SerialMessage::new(test_bytes.to_vec(), server_addr))
+        .unwrap();
+
+    let (buffer, _stream) = io_loop.block_on(stream.into_future()).ok().unwrap();
+    assert_eq!(buffer.expect("no buffer received").bytes(), test_bytes);
+
+    server_handle.join().expect("server thread failed");
+}
+
+#[test]
+fn test_udp_client_stream_tcp_fallback() {
+    use std::io::{Read, Write};
+    use tokio::runtime::current_thread::Runtime;
+
+    use op::{Message, MessageType};
+
+    let loopback = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+    let udp_server = std::net::UdpSocket::bind(SocketAddr::new(loopback, 0)).unwrap();
+    let name_server = udp_server.local_addr().unwrap();
+    // UDP and TCP occupy independent port spaces, so the fallback can reuse the same port
+    let tcp_listener = std::net::TcpListener::bind(name_server).unwrap();
+
+    let udp_handle = std::thread::Builder::new()
+        .name("test_udp_client_stream_tcp_fallback:udp".to_string())
+        .spawn(move || {
+            let mut buffer = [0_u8; 512];
+            let (len, addr) = udp_server.recv_from(&mut buffer).expect("udp receive failed");
+
+            let query = Message::from_vec(&buffer[0..len]).expect("failed to parse query");
+            let mut truncated = Message::new();
+            truncated.set_id(query.id());
+            truncated.set_message_type(MessageType::Response);
+            truncated.header_mut().set_truncated(true);
+
+            let response = truncated
+                .to_vec()
+                .expect("failed to serialize truncated response");
+            udp_server
+                .send_to(&response, addr)
+                .expect("udp send failed");
+        }).unwrap();
+
+    let tcp_handle = std::thread::Builder::new()
+        .name("test_udp_client_stream_tcp_fallback:tcp".to_string())
+        .spawn(move || {
+            let (mut socket, _addr) = tcp_listener.accept().expect("tcp accept failed");
+
+            let mut len_buf = [0_u8; 2];
+            socket.read_exact(&mut len_buf).expect("tcp length read failed");
+            let len = (u16::from(len_buf[0]) << 8 | u16::from(len_buf[1])) as usize;
+
+            let mut buffer = vec![0_u8; len];
+            socket
+                .read_exact(&mut buffer)
+                .expect("tcp message read failed");
+
+            let query = Message::from_vec(&buffer).expect("failed to parse tcp query");
+            assert_eq!(
+                query.message_type(),
+                MessageType::Query,
+                "TCP fallback should replay the original query, not the truncated UDP response"
+            );
+            let mut full = Message::new();
+            full.set_id(query.id());
+            full.set_message_type(MessageType::Response);
+
+            let response = full.to_vec().expect("failed to serialize tcp response");
+            let len = response.len() as u16;
+            let mut framed = vec![(len >> 8) as u8, len as u8];
+            framed.extend_from_slice(&response);
+            socket.write_all(&framed).expect("tcp send failed");
+        }).unwrap();
+
+    let mut io_loop = Runtime::new().unwrap();
+    let (stream, mut sender) = UdpClientStream::with_tcp_fallback(name_server);
+    let stream: UdpClientStream = io_loop.block_on(stream).ok().unwrap();
+
+    let mut query = Message::new();
+    query.set_id(1);
+    query.set_message_type(MessageType::Query);
+    let query_bytes = query.to_vec().expect("failed to serialize query");
+
+    sender
+        .send(SerialMessage::new(query_bytes, name_server))
+        .unwrap();
+
+    let (buffer, _stream) = io_loop.block_on(stream.into_future()).ok().unwrap();
+    let response = Message::from_vec(buffer.expect("no buffer received").bytes())
+        .expect("failed to parse final response");
+    assert!(
+        !response.header().truncated(),
+        "fallback response should not be truncated"
+    );
+
+    udp_handle.join().expect("udp thread failed");
+    tcp_handle.join().expect("tcp thread failed");
+}
+
+#[test]
+fn test_udp_client_stream_tcp_fallback_correlates_pipelined_queries_by_id() {
+    use std::io::{Read, Write};
+    use tokio::runtime::current_thread::Runtime;
+
+    use op::{Message, MessageType};
+
+    let loopback = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+    let udp_server = std::net::UdpSocket::bind(SocketAddr::new(loopback, 0)).unwrap();
+    let name_server = udp_server.local_addr().unwrap();
+    let tcp_listener = std::net::TcpListener::bind(name_server).unwrap();
+
+    const TRUNCATED_QUERY_ID: u16 = 1;
+    const FULL_QUERY_ID: u16 = 2;
+
+    let udp_handle = std::thread::Builder::new()
+        .name("test_udp_client_stream_tcp_fallback_correlates_pipelined_queries_by_id:udp".to_string())
+        .spawn(move || {
+            let mut buffer = [0_u8; 512];
+
+            for _ in 0..2 {
+                let (len, addr) = udp_server.recv_from(&mut buffer).expect("udp receive failed");
+                let query = Message::from_vec(&buffer[0..len]).expect("failed to parse query");
+
+                let mut response = Message::new();
+                response.set_id(query.id());
+                response.set_message_type(MessageType::Response);
+                if query.id() == TRUNCATED_QUERY_ID {
+                    response.header_mut().set_truncated(true);
+                }
+
+                let response = response.to_vec().expect("failed to serialize udp response");
+                udp_server
+                    .send_to(&response, addr)
+                    .expect("udp send failed");
+            }
+        }).unwrap();
+
+    let tcp_handle = std::thread::Builder::new()
+        .name("test_udp_client_stream_tcp_fallback_correlates_pipelined_queries_by_id:tcp".to_string())
+        .spawn(move || {
+            let (mut socket, _addr) = tcp_listener.accept().expect("tcp accept failed");
+
+            let mut len_buf = [0_u8; 2];
+            socket.read_exact(&mut len_buf).expect("tcp length read failed");
+            let len = (u16::from(len_buf[0]) << 8 | u16::from(len_buf[1])) as usize;
+
+            let mut buffer = vec![0_u8; len];
+            socket
+                .read_exact(&mut buffer)
+                .expect("tcp message read failed");
+
+            let query = Message::from_vec(&buffer).expect("failed to parse tcp query");
+            assert_eq!(
+                query.id(),
+                TRUNCATED_QUERY_ID,
+                "TCP fallback replayed the wrong pipelined query"
+            );
+
+            let mut full = Message::new();
+            full.set_id(query.id());
+            full.set_message_type(MessageType::Response);
+
+            let response = full.to_vec().expect("failed to serialize tcp response");
+            let len = response.len() as u16;
+            let mut framed = vec![(len >> 8) as u8, len as u8];
+            framed.extend_from_slice(&response);
+            socket.write_all(&framed).expect("tcp send failed");
+        }).unwrap();
+
+    let mut io_loop = Runtime::new().unwrap();
+    let (stream, mut sender) = UdpClientStream::with_tcp_fallback(name_server);
+    let mut stream: UdpClientStream = io_loop.block_on(stream).ok().unwrap();
+
+    let mut truncated_query = Message::new();
+    truncated_query.set_id(TRUNCATED_QUERY_ID);
+    truncated_query.set_message_type(MessageType::Query);
+    sender
+        .send(SerialMessage::new(
+            truncated_query.to_vec().expect("failed to serialize query"),
+            name_server,
+        )).unwrap();
+
+    let mut full_query = Message::new();
+    full_query.set_id(FULL_QUERY_ID);
+    full_query.set_message_type(MessageType::Query);
+    sender
+        .send(SerialMessage::new(
+            full_query.to_vec().expect("failed to serialize query"),
+            name_server,
+        )).unwrap();
+
+    let mut seen_ids = std::collections::HashSet::new();
+    for _ in 0..2 {
+        let (buffer, stream_tmp) = io_loop.block_on(stream.into_future()).ok().unwrap();
+        stream = stream_tmp;
+        let response = Message::from_vec(buffer.expect("no buffer received").bytes())
+            .expect("failed to parse response");
+        seen_ids.insert(response.id());
+    }
+
+    assert_eq!(seen_ids, [TRUNCATED_QUERY_ID, FULL_QUERY_ID].iter().cloned().collect());
+
+    udp_handle.join().expect("udp thread failed");
+    tcp_handle.join().expect("tcp thread failed");
+}
+
+#[test]
+fn test_udp_client_stream_large_payload() {
+    use tokio::runtime::current_thread::Runtime;
+
+    // larger than the classic 512-byte UDP response limit
+    let test_bytes: Vec<u8> = (0..2048).map(|i| (i % 256) as u8).collect();
+
+    let server = std::net::UdpSocket::bind(SocketAddr::new(
+        IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+        0,
+    )).unwrap();
+    let server_addr = server.local_addr().unwrap();
+
+    let expected = test_bytes.clone();
+    let server_handle = std::thread::Builder::new()
+        .name("test_udp_client_stream_large_payload:server".to_string())
+        .spawn(move || {
+            let mut buffer = vec![0_u8; 4096];
+            let (len, addr) = server.recv_from(&mut buffer).expect("receive failed");
+            assert_eq!(&buffer[0..len], expected.as_slice());
+
+            server
+                .send_to(&buffer[0..len], addr)
+                .expect("send failed");
+        }).unwrap();
+
+    let mut io_loop = Runtime::new().unwrap();
+    let (stream, mut sender) = UdpClientStream::with_buffer_size(server_addr, 4096);
+    let stream: UdpClientStream = io_loop.block_on(stream).ok().unwrap();
+    assert_eq!(stream.max_payload_len(), 4096);
+
+    sender
+        .send(SerialMessage::new(test_bytes.clone(), server_addr))
+        .unwrap();
+
+    let (buffer, _stream) = io_loop.block_on(stream.into_future()).ok().unwrap();
+    assert_eq!(buffer.expect("no buffer received").bytes(), test_bytes.as_slice());
+
+    server_handle.join().expect("server thread failed");
+}
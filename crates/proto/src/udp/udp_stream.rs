@@ -0,0 +1,152 @@
+// Copyright 2015-2018 Benjamin Fry <benjaminfry@me.com>
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::io;
+use std::net::SocketAddr;
+
+use futures::sync::mpsc::{self, UnboundedReceiver};
+use futures::{Async, Future, Poll, Stream};
+use tokio::net::UdpSocket as TokioUdpSocket;
+
+use op::{Edns, Message};
+use udp::udp_client_stream::bind_random_port;
+use xfer::SerialMessage;
+use BufDnsStreamHandle;
+use DnsStreamHandle;
+
+/// The default maximum UDP payload size to receive, in bytes; matches `UdpClientStream`'s own
+///  default so `UdpStream::new` and `UdpClientStream::new` stay in sync.
+const DEFAULT_MAX_PAYLOAD_LEN: usize = 4096;
+
+/// A persistent, randomized-source-port UDP socket shared by every query sent on the
+///  `UdpClientStream` that owns it.
+#[must_use = "futures do nothing unless polled"]
+pub struct UdpStream {
+    socket: TokioUdpSocket,
+    outbound_messages: UnboundedReceiver<SerialMessage>,
+    send_state: Option<SerialMessage>,
+    max_payload_len: usize,
+}
+
+impl UdpStream {
+    /// Constructs a new UdpStream bound to a randomized local port, ready to send queries to and
+    ///  receive responses from `name_server`. Uses the default maximum receive payload length;
+    ///  see `with_max_payload_len` to specify another value.
+    ///
+    /// # Return
+    ///
+    /// a tuple of a Future which resolves to the bound `UdpStream`, and a handle which can be
+    ///  used to send messages into it.
+    pub fn new(name_server: SocketAddr) -> (UdpStreamConnect, Box<DnsStreamHandle + Send>) {
+        Self::with_max_payload_len(name_server, DEFAULT_MAX_PAYLOAD_LEN)
+    }
+
+    /// Constructs a new UdpStream as with `new`, but with its receive buffer (and the EDNS0 OPT
+    ///  record advertised on every outgoing query) sized to `max_payload_len` bytes rather than
+    ///  the default.
+    pub fn with_max_payload_len(
+        name_server: SocketAddr,
+        max_payload_len: usize,
+    ) -> (UdpStreamConnect, Box<DnsStreamHandle + Send>) {
+        let (message_sender, outbound_messages) = mpsc::unbounded();
+
+        let connect = UdpStreamConnect {
+            bind_addr: name_server,
+            outbound_messages: Some(outbound_messages),
+            max_payload_len,
+        };
+
+        let sender = Box::new(BufDnsStreamHandle::new(name_server, message_sender));
+
+        (connect, sender)
+    }
+}
+
+impl Stream for UdpStream {
+    type Item = SerialMessage;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            if let Some(message) = self.send_state.take() {
+                let dest = message.addr();
+                let bytes = set_edns_max_payload_len(message.bytes(), self.max_payload_len);
+
+                match self.socket.poll_send_to(&bytes, &dest)? {
+                    Async::Ready(_) => {}
+                    Async::NotReady => {
+                        self.send_state = Some(message);
+                        break;
+                    }
+                }
+            }
+
+            match self.outbound_messages.poll() {
+                Ok(Async::Ready(Some(message))) => {
+                    self.send_state = Some(message);
+                    continue;
+                }
+                Ok(Async::Ready(None)) | Err(()) => return Ok(Async::Ready(None)),
+                Ok(Async::NotReady) => break,
+            }
+        }
+
+        let mut buffer = vec![0_u8; self.max_payload_len];
+        match self.socket.poll_recv_from(&mut buffer)? {
+            Async::Ready((len, addr)) => Ok(Async::Ready(Some(SerialMessage::new(
+                buffer[..len].to_vec(),
+                addr,
+            )))),
+            Async::NotReady => Ok(Async::NotReady),
+        }
+    }
+}
+
+/// Parses `bytes` as a DNS message and sets its EDNS0 OPT record's advertised UDP payload size
+///  to `max_payload_len`, adding an OPT record if the message didn't already carry one, so the
+///  name server knows it may reply with a datagram up to this stream's receive buffer size. If
+///  `bytes` doesn't parse as a DNS message, it is returned unmodified; the name server will
+///  reject it on its own terms rather than have us silently swallow it here.
+fn set_edns_max_payload_len(bytes: &[u8], max_payload_len: usize) -> Vec<u8> {
+    let mut message = match Message::from_vec(bytes) {
+        Ok(message) => message,
+        Err(_) => return bytes.to_vec(),
+    };
+
+    let mut edns = message.edns().cloned().unwrap_or_else(Edns::new);
+    edns.set_max_payload(max_payload_len as u16);
+    message.set_edns(edns);
+
+    message.to_vec().unwrap_or_else(|_| bytes.to_vec())
+}
+
+/// A future that resolves to a bound `UdpStream`
+#[must_use = "futures do nothing unless polled"]
+pub struct UdpStreamConnect {
+    bind_addr: SocketAddr,
+    outbound_messages: Option<UnboundedReceiver<SerialMessage>>,
+    max_payload_len: usize,
+}
+
+impl Future for UdpStreamConnect {
+    type Item = UdpStream;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let socket = bind_random_port(self.bind_addr)?;
+
+        Ok(Async::Ready(UdpStream {
+            socket,
+            outbound_messages: self
+                .outbound_messages
+                .take()
+                .expect("UdpStreamConnect polled after completion"),
+            send_state: None,
+            max_payload_len: self.max_payload_len,
+        }))
+    }
+}